@@ -1,4 +1,5 @@
 extern crate colored;
+extern crate libc;
 extern crate termion;
 extern crate ultrastar_txt;
 
@@ -9,21 +10,102 @@ use errors::*;
 
 use colored::*;
 use pitch_calc::*;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::ops;
+use std::os::unix::io::AsRawFd;
+
+/// size of the given terminal as (columns, rows)
+/// termion::terminal_size only asks stdout, which fails when stdout is redirected
+pub fn terminal_size(tty: &File) -> Result<(u16, u16)> {
+    unsafe {
+        let mut size: libc::winsize = mem::zeroed();
+        if libc::ioctl(tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) == -1 {
+            return Err(io::Error::last_os_error()).chain_err(|| "could not get terminal size");
+        }
+        Ok((size.ws_col, size.ws_row))
+    }
+}
+
+/// terminal in raw mode, the previous mode is restored when it is dropped
+/// termion's raw mode always switches stdin, which fails when stdin is not a terminal
+pub struct RawTty {
+    tty: File,
+    original: libc::termios,
+}
+
+impl RawTty {
+    pub fn new(tty: File) -> Result<RawTty> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(tty.as_raw_fd(), &mut original) == -1 {
+                return Err(io::Error::last_os_error())
+                    .chain_err(|| "could not get terminal attributes");
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &raw) == -1 {
+                return Err(io::Error::last_os_error())
+                    .chain_err(|| "could not switch terminal to raw mode");
+            }
+            Ok(RawTty {
+                tty: tty,
+                original: original,
+            })
+        }
+    }
+}
+
+impl Drop for RawTty {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+impl Write for RawTty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tty.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tty.flush()
+    }
+}
+
+impl ops::Deref for RawTty {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.tty
+    }
+}
 
 pub fn generate_screen(
     line: &ultrastar_txt::Line,
     beat: f32,
+    term_width: u16,
     dominant_note: Option<LetterOctave>,
+    ghost_note: Option<LetterOctave>,
+    mirror: bool,
+    golden_style: GoldenStyle,
 ) -> Result<String> {
-    let (term_width, _term_height) =
-        termion::terminal_size().chain_err(|| "could not get terminal size")?;
-    let note_lines = draw_notelines(line, beat, term_width)?;
-    let lyric_line = gen_lyric_line(line, beat, term_width, dominant_note);
+    let note_lines = draw_notelines(line, beat, term_width, ghost_note, mirror, golden_style)?;
+    let lyric_line = gen_lyric_line(line, beat, term_width, dominant_note, mirror);
 
     Ok(format!("{}{}", note_lines, lyric_line,))
 }
 
-fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Result<String> {
+fn draw_notelines(
+    line: &ultrastar_txt::Line,
+    beat: f32,
+    term_width: u16,
+    ghost_note: Option<LetterOctave>,
+    mirror: bool,
+    golden_style: GoldenStyle,
+) -> Result<String> {
     // spacin between note lines
     let line_spacing = 2;
     // space to leave at the top (ex for progrss bar)
@@ -64,26 +146,28 @@ fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Res
                 duration,
                 pitch: _,
                 text: _,
-            } => start + duration,
+            } => start.saturating_add(duration),
             &ultrastar_txt::Note::Golden {
                 start,
                 duration,
                 pitch: _,
                 text: _,
-            } => start + duration,
+            } => start.saturating_add(duration),
             &ultrastar_txt::Note::Freestyle {
                 start,
                 duration,
                 pitch: _,
                 text: _,
-            } => start + duration,
+            } => start.saturating_add(duration),
             &ultrastar_txt::Note::PlayerChange { player: _ } => 0, // TODO: this is bad find better solution
         }
     } else {
         return Err("line has no last note???".into());
     };
 
-    let chars_per_beat = term_width as f32 / (last_note_end - first_note_start) as f32;
+    // a line without length would divide by zero
+    let line_beats = last_note_end.saturating_sub(first_note_start).max(1);
+    let chars_per_beat = term_width as f32 / line_beats as f32;
 
     for note in line.notes.iter() {
         let (start, duration, pitch, note_type) = match note {
@@ -110,14 +194,47 @@ fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Res
 
         // calculate position of current note
         // terminal goto starts at 1
-        let note_hpos = ((start - first_note_start) as f32 * chars_per_beat) as u16 + 1;
+        let note_offset = start.saturating_sub(first_note_start) as f32 * chars_per_beat;
+        let note_hpos = (note_offset as u16).saturating_add(1);
         let note_vpos =
             (top_offset + 17 * line_spacing) - letter_to_pos(pitch.letter()) * line_spacing + 1;
 
-        let color_note = match note_type {
-            NoteType::Golden => {
-                Box::new(|note: &str| note.yellow().to_string()) as Box<Fn(&str) -> String>
+        // horizontal position of something starting at the note with the given length
+        let hpos = |len: usize| {
+            if mirror {
+                mirror_hpos(note_hpos, len, term_width)
+            } else {
+                note_hpos
             }
+        };
+        // bars never need to be wider than the terminal
+        let max_len = term_width as usize;
+        let note_len = ((duration as f32 * chars_per_beat) as usize).min(max_len);
+
+        // extra emphasis for golden notes besides their color
+        let golden = note_type == NoteType::Golden;
+        let glyph = if golden && golden_style == GoldenStyle::Glyph {
+            "*"
+        } else {
+            "#"
+        };
+        let blink = golden && golden_style == GoldenStyle::Blink;
+        let star = if golden && golden_style == GoldenStyle::Star {
+            "★"
+        } else {
+            ""
+        };
+
+        let label = if mirror {
+            mirror_str(&format!("{}{:?}", star, pitch.letter()))
+        } else {
+            format!("{}{:?}", star, pitch.letter())
+        };
+
+        let color_note = match note_type {
+            NoteType::Golden => Box::new(move |note: &str| {
+                blink_if(blink, note.yellow().to_string())
+            }) as Box<Fn(&str) -> String>,
             NoteType::Regular => {
                 Box::new(|note: &str| note.bright_blue().to_string()) as Box<Fn(&str) -> String>
             }
@@ -127,9 +244,9 @@ fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Res
         };
 
         let color_played_note = match note_type {
-            NoteType::Golden => {
-                Box::new(|note: &str| note.bright_yellow().to_string()) as Box<Fn(&str) -> String>
-            }
+            NoteType::Golden => Box::new(move |note: &str| {
+                blink_if(blink, note.bright_yellow().to_string())
+            }) as Box<Fn(&str) -> String>,
             NoteType::Regular => {
                 Box::new(|note: &str| note.white().to_string()) as Box<Fn(&str) -> String>
             }
@@ -143,60 +260,79 @@ fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Res
             // draw progress bar
             let times = (beat - start as f32) * chars_per_beat;
             if beat <= last_note_end as f32 {
-                let bar = "#".repeat(times.floor() as usize);
+                let bar = "#".repeat((times.floor() as usize).min(max_len));
                 // terminal goto starts with 1
-                output.push_str(format!("{}{}", termion::cursor::Goto(1, 1), bar).as_ref());
+                let bar_hpos = if mirror {
+                    mirror_hpos(1, bar.len(), term_width)
+                } else {
+                    1
+                };
+                output.push_str(format!("{}{}", termion::cursor::Goto(bar_hpos, 1), bar).as_ref());
             }
 
             // note is current note -> hightlight it
-            if (start + duration) as f32 >= beat {
-                let marked = (beat - start as f32) * chars_per_beat;
-                let note_line_str = color_note(
-                    "#".repeat((duration as f32 * chars_per_beat) as usize)
-                        .as_ref(),
-                );
-                let marked_line_str = color_played_note("#".repeat(marked as usize).as_ref());
+            if start.saturating_add(duration) as f32 >= beat {
+                let marked = (((beat - start as f32) * chars_per_beat) as usize).min(max_len);
+                let note_line_str = color_note(glyph.repeat(note_len).as_ref());
+                let marked_line_str = color_played_note(glyph.repeat(marked).as_ref());
                 output.push_str(
                     format!(
-                        "{}{}{}{}{}{:?}",
-                        termion::cursor::Goto(note_hpos, note_vpos),
+                        "{}{}{}{}{}{}",
+                        termion::cursor::Goto(hpos(note_len), note_vpos),
                         note_line_str,
-                        termion::cursor::Goto(note_hpos, note_vpos),
+                        termion::cursor::Goto(hpos(marked), note_vpos),
                         marked_line_str,
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        pitch.letter(),
+                        termion::cursor::Goto(hpos(label.chars().count()), note_vpos),
+                        label,
                     ).as_ref(),
                 );
             }
             // note has been played
             else {
-                let played_line_str = color_played_note(
-                    "#".repeat((duration as f32 * chars_per_beat) as usize)
-                        .as_ref(),
-                );
+                let played_line_str = color_played_note(glyph.repeat(note_len).as_ref());
                 output.push_str(
                     format!(
-                        "{}{}{}{:?}",
-                        termion::cursor::Goto(note_hpos, note_vpos),
+                        "{}{}{}{}",
+                        termion::cursor::Goto(hpos(note_len), note_vpos),
                         played_line_str,
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        pitch.letter(),
+                        termion::cursor::Goto(hpos(label.chars().count()), note_vpos),
+                        label,
                     ).as_ref(),
                 );
             }
         // note has not been played yet
         } else {
-            let note_line_str = color_note(
-                "#".repeat((duration as f32 * chars_per_beat) as usize)
-                    .as_ref(),
-            );
+            let note_line_str = color_note(glyph.repeat(note_len).as_ref());
             output.push_str(
                 format!(
-                    "{}{}{}{:?}",
-                    termion::cursor::Goto(note_hpos, note_vpos),
+                    "{}{}{}{}",
+                    termion::cursor::Goto(hpos(note_len), note_vpos),
                     note_line_str,
-                    termion::cursor::Goto(note_hpos, note_vpos),
-                    pitch.letter(),
+                    termion::cursor::Goto(hpos(label.chars().count()), note_vpos),
+                    label,
+                ).as_ref(),
+            );
+        }
+    }
+
+    // faint marker for what was sung at this position in an earlier run
+    if let Some(ghost) = ghost_note {
+        if beat >= first_note_start as f32 && beat <= last_note_end as f32 {
+            // terminal goto starts at 1
+            let ghost_hpos =
+                (((beat - first_note_start as f32) * chars_per_beat) as u16).saturating_add(1);
+            let ghost_hpos = if mirror {
+                mirror_hpos(ghost_hpos, 1, term_width)
+            } else {
+                ghost_hpos
+            };
+            let ghost_vpos =
+                (top_offset + 17 * line_spacing) - letter_to_pos(ghost.letter()) * line_spacing + 1;
+            output.push_str(
+                format!(
+                    "{}{}",
+                    termion::cursor::Goto(ghost_hpos, ghost_vpos),
+                    "o".bright_black(),
                 ).as_ref(),
             );
         }
@@ -233,6 +369,26 @@ fn line_to_str(line: &ultrastar_txt::Line) -> String {
     line_str
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum GoldenStyle {
+    Color,
+    Star,
+    Blink,
+    Glyph,
+}
+
+impl GoldenStyle {
+    pub fn from_arg(arg: &str) -> Option<GoldenStyle> {
+        match arg {
+            "color" => Some(GoldenStyle::Color),
+            "star" => Some(GoldenStyle::Star),
+            "blink" => Some(GoldenStyle::Blink),
+            "glyph" => Some(GoldenStyle::Glyph),
+            _ => None,
+        }
+    }
+}
+
 #[derive(PartialEq)]
 enum NoteType {
     Regular,
@@ -245,15 +401,23 @@ fn gen_lyric_line(
     beat: f32,
     term_width: u16,
     dominant_note: Option<LetterOctave>,
+    mirror: bool,
 ) -> String {
     let uncolored_line = line_to_str(line);
 
     // terminal goto starts at 1
-    let line_vpos = (term_width - uncolored_line.len() as u16) / 2 + 1;
+    // lines wider than the terminal start at the left border
+    let line_vpos = term_width.saturating_sub(uncolored_line.len() as u16) / 2 + 1;
     let line_hpos = 2 + 17 * 2 + 10 + 1; // TODO this is below the lines but should not be a magic number
 
     let mut lyric = format!("{}", termion::cursor::Goto(line_vpos, line_hpos));
-    for note in line.notes.iter() {
+    // mirrored lyrics are written back to front
+    let notes: Vec<_> = if mirror {
+        line.notes.iter().rev().collect()
+    } else {
+        line.notes.iter().collect()
+    };
+    for note in notes {
         let (start, duration, _pitch, text, note_type) = match note {
             &ultrastar_txt::Note::Regular {
                 start,
@@ -275,11 +439,16 @@ fn gen_lyric_line(
             } => (start, duration, pitch, text, NoteType::Freestyle),
             _ => continue,
         };
+        let text = &if mirror {
+            mirror_str(text)
+        } else {
+            text.clone()
+        };
 
         // note is current note or allready played
         if beat >= start as f32 {
             // note is current note -> hightlight it
-            if (start + duration) as f32 >= beat {
+            if start.saturating_add(duration) as f32 >= beat {
                 if note_type == NoteType::Golden {
                     lyric.push_str(&text.black().on_bright_yellow().to_string());
                 } else {
@@ -307,13 +476,36 @@ fn gen_lyric_line(
         Some(n) => format!("{:?}", n),
         None => format!("                    "),
     };
+    let note = if mirror { mirror_str(&note) } else { note };
     let line_hpos = 2 + 17 * 2 + 10 + 3; // TODO this is below the lines but should not be a magic number
-    let line_vpos = (term_width - note.len() as u16) / 2 + 1;
+    let line_vpos = term_width.saturating_sub(note.len() as u16) / 2 + 1;
     lyric.push_str(format!("{}{}", termion::cursor::Goto(line_vpos, line_hpos), note).as_ref());
 
     lyric
 }
 
+/// horizontal position of something of the given length when the screen is flipped
+fn mirror_hpos(hpos: u16, len: usize, term_width: u16) -> u16 {
+    // terminal goto starts at 1
+    let len = len.min(u16::MAX as usize) as u16;
+    term_width
+        .saturating_add(2)
+        .saturating_sub(hpos.saturating_add(len))
+        .max(1)
+}
+
+fn blink_if(blink: bool, text: String) -> String {
+    if blink {
+        format!("{}{}{}", termion::style::Blink, text, termion::style::NoBlink)
+    } else {
+        text
+    }
+}
+
+fn mirror_str(text: &str) -> String {
+    text.chars().rev().collect()
+}
+
 fn letter_to_pos(letter: Letter) -> u16 {
     match letter {
         Letter::C => 0,
@@ -335,3 +527,141 @@ fn letter_to_pos(letter: Letter) -> u16 {
         Letter::B => 16,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TERM_WIDTH: u16 = 80;
+
+    fn regular(start: i32, duration: i32, pitch: i32) -> ultrastar_txt::Note {
+        ultrastar_txt::Note::Regular {
+            start: start,
+            duration: duration,
+            pitch: pitch,
+            text: "la".to_string(),
+        }
+    }
+
+    fn golden(start: i32, duration: i32, pitch: i32) -> ultrastar_txt::Note {
+        ultrastar_txt::Note::Golden {
+            start: start,
+            duration: duration,
+            pitch: pitch,
+            text: "la".to_string(),
+        }
+    }
+
+    fn line(notes: Vec<ultrastar_txt::Note>) -> ultrastar_txt::Line {
+        ultrastar_txt::Line {
+            start: 0,
+            rel: None,
+            notes: notes,
+        }
+    }
+
+    /// (row, column) of every cursor goto in the output
+    fn gotos(output: &str) -> Vec<(u16, u16)> {
+        output
+            .split("\x1b[")
+            .skip(1)
+            .filter_map(|part| {
+                let end = part.find('H')?;
+                let mut fields = part[..end].split(';');
+                let row = fields.next()?.parse().ok()?;
+                let column = fields.next()?.parse().ok()?;
+                Some((row, column))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mirror_hpos_flips_span() {
+        // columns 1..16 become 65..80
+        assert_eq!(mirror_hpos(1, 16, TERM_WIDTH), 65);
+        assert_eq!(mirror_hpos(41, 40, TERM_WIDTH), 1);
+        assert_eq!(mirror_hpos(80, 1, TERM_WIDTH), 1);
+    }
+
+    #[test]
+    fn mirrored_notes_are_placed_from_the_right() {
+        // 20 beats on 80 columns, so 4 columns per beat
+        let line = line(vec![regular(0, 4, 2), golden(10, 10, 5)]);
+        let normal =
+            draw_notelines(&line, -1.0, TERM_WIDTH, None, false, GoldenStyle::Star).unwrap();
+        let mirrored =
+            draw_notelines(&line, -1.0, TERM_WIDTH, None, true, GoldenStyle::Star).unwrap();
+        let normal_gotos = gotos(&normal);
+        let mirrored_gotos = gotos(&mirrored);
+        assert_eq!(normal_gotos.len(), mirrored_gotos.len());
+
+        // bar and label for every note, the bar comes first
+        for (index, &(bar_len, label_len)) in [(16, 1), (40, 2)].iter().enumerate() {
+            let (row, x) = normal_gotos[index * 2];
+            assert_eq!(mirrored_gotos[index * 2], (row, TERM_WIDTH + 2 - (x + bar_len)));
+            let (row, x) = normal_gotos[index * 2 + 1];
+            assert_eq!(
+                mirrored_gotos[index * 2 + 1],
+                (row, TERM_WIDTH + 2 - (x + label_len))
+            );
+        }
+        assert_eq!(normal_gotos[0].1, 1);
+        assert_eq!(normal_gotos[1].1, 1);
+        assert_eq!(normal_gotos[2].1, 41);
+        assert_eq!(normal_gotos[3].1, 41);
+
+        // labels are written back to front, so the marker ends up on the right
+        assert!(normal.contains("★F"));
+        assert!(mirrored.contains("F★"));
+    }
+
+    #[test]
+    fn golden_marker_only_on_golden_notes() {
+        let line = line(vec![regular(0, 4, 2), golden(10, 10, 5)]);
+
+        let star = draw_notelines(&line, -1.0, TERM_WIDTH, None, false, GoldenStyle::Star).unwrap();
+        assert_eq!(star.matches("★").count(), 1);
+        // the last goto before the marker is the label of the golden note
+        let before_star = &star[..star.find("★").unwrap()];
+        assert_eq!(gotos(before_star).last().unwrap().1, 41);
+
+        let color =
+            draw_notelines(&line, -1.0, TERM_WIDTH, None, false, GoldenStyle::Color).unwrap();
+        assert!(!color.contains("★"));
+        assert!(!color.contains("*"));
+        assert!(!color.contains(&termion::style::Blink.to_string()));
+    }
+
+    #[test]
+    fn notes_at_the_end_of_i32_do_not_overflow() {
+        // the golden note ends far past i32::MAX
+        let start = i32::MAX - 8;
+        let line = line(vec![regular(start, 2, 0), golden(start + 2, i32::MAX, 7)]);
+        for &beat in &[start as f32 - 1.0, start as f32, i32::MAX as f32, f32::MAX] {
+            let output =
+                draw_notelines(&line, beat, TERM_WIDTH, None, false, GoldenStyle::Color).unwrap();
+            // progress bar, bar and highlight are never wider than the terminal
+            assert!(output.matches('#').count() <= 3 * TERM_WIDTH as usize * line.notes.len());
+        }
+    }
+
+    #[test]
+    fn screen_does_not_need_stdout() {
+        // stdout may be redirected to a file, only the given width is used
+        let line = line(vec![regular(0, 4, 2), golden(10, 10, 5)]);
+        let screen = generate_screen(&line, 5.0, 40, None, None, false, GoldenStyle::Color);
+        assert!(screen.unwrap().contains("la"));
+    }
+
+    #[test]
+    fn terminal_size_of_file_fails() {
+        let file = File::open("/dev/null").unwrap();
+        assert!(terminal_size(&file).is_err());
+    }
+
+    #[test]
+    fn raw_mode_of_file_fails() {
+        let file = File::open("/dev/null").unwrap();
+        assert!(RawTty::new(file).is_err());
+    }
+}
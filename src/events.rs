@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use errors::*;
+use pitch_calc::*;
+
+/// writes the detected note of every frame to a file, one `<position ms> <step>` per line
+pub struct EventLog {
+    writer: BufWriter<File>,
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> Result<EventLog> {
+        let file = File::create(path).chain_err(|| "could not create event log")?;
+        Ok(EventLog {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn log(&mut self, position_ms: u64, note: Option<LetterOctave>) -> Result<()> {
+        match note {
+            Some(note) => writeln!(self.writer, "{} {}", position_ms, note.step()),
+            None => writeln!(self.writer, "{} -", position_ms),
+        }.chain_err(|| "could not write to event log")
+    }
+
+    /// flushes the log, dropping it would silently ignore a failed last write
+    pub fn finish(mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .chain_err(|| "could not write to event log")
+    }
+}
+
+/// detected notes of an earlier run read back from an event log
+pub struct Ghost {
+    events: Vec<(u64, Option<LetterOctave>)>,
+}
+
+impl Ghost {
+    pub fn load(path: &Path) -> Result<Ghost> {
+        let file = File::open(path).chain_err(|| "could not open ghost file")?;
+        Ghost::read(BufReader::new(file))
+    }
+
+    fn read<R: BufRead>(reader: R) -> Result<Ghost> {
+        let mut events = Vec::new();
+        for line in reader.lines() {
+            let line = line.chain_err(|| "could not read ghost file")?;
+            let mut fields = line.split_whitespace();
+            let position_ms = match fields.next() {
+                Some(field) => field
+                    .parse::<u64>()
+                    .chain_err(|| format!("invalid position in ghost file: {}", line))?,
+                // skip empty lines
+                None => continue,
+            };
+            let note = match fields.next() {
+                Some("-") | None => None,
+                Some(field) => Some(
+                    Step(field
+                        .parse::<f32>()
+                        .chain_err(|| format!("invalid note in ghost file: {}", line))?)
+                        .to_letter_octave(),
+                ),
+            };
+            events.push((position_ms, note));
+        }
+        // runs are logged in order, but make sure binary search works
+        events.sort_by_key(|&(position_ms, _)| position_ms);
+        Ok(Ghost { events: events })
+    }
+
+    /// note of the earlier run at the given song position,
+    /// positions outside of the recording are clamped to its first or last frame
+    pub fn note_at(&self, position_ms: u64) -> Option<LetterOctave> {
+        let index = match self.events
+            .binary_search_by_key(&position_ms, |&(position_ms, _)| position_ms)
+        {
+            Ok(index) => index,
+            Err(0) => 0,
+            Err(index) => index - 1,
+        };
+        self.events.get(index).and_then(|&(_, note)| note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &'static str = "1000 12\n1100 -\n\n1200 19\n";
+
+    #[test]
+    fn rests_and_blank_lines_are_parsed() {
+        let ghost = Ghost::read(LOG.as_bytes()).unwrap();
+        assert_eq!(ghost.events.len(), 3);
+        assert_eq!(ghost.events[1], (1100, None));
+        assert!(Ghost::read("1000 x\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn positions_are_clamped_to_the_recording() {
+        let ghost = Ghost::read(LOG.as_bytes()).unwrap();
+        let first = Some(Step(12.0).to_letter_octave());
+        let last = Some(Step(19.0).to_letter_octave());
+
+        // before the first and after the last frame
+        assert_eq!(ghost.note_at(0), first);
+        assert_eq!(ghost.note_at(999_999), last);
+        // between frames the earlier one is shown
+        assert_eq!(ghost.note_at(1000), first);
+        assert_eq!(ghost.note_at(1050), first);
+        assert_eq!(ghost.note_at(1150), None);
+        assert_eq!(ghost.note_at(1200), last);
+    }
+
+    #[test]
+    fn empty_recording_shows_nothing() {
+        let ghost = Ghost::read("".as_bytes()).unwrap();
+        assert_eq!(ghost.note_at(1000), None);
+    }
+}
@@ -14,9 +14,12 @@ extern crate termion;
 extern crate ultrastar_txt;
 
 mod draw;
+mod events;
 mod pitch;
+mod score;
+mod timing;
 
-use std::io::{stdout, Write};
+use std::io::{Read, Write};
 use std::path::Path;
 use gst::MessageView;
 use gst::prelude::*;
@@ -74,16 +77,71 @@ fn run() -> Result<()> {
     let matches = App::new("usrs-cli")
         .version(VERSION)
         .author(AUTHOR)
-        .about("An Ultrastar song player for the command line written in rust")
+        .about(
+            "An Ultrastar song player for the command line written in rust\n\
+             Press q or ctrl-c to end the song early, reports are still printed.",
+        )
         .arg(
             Arg::with_name("songfile")
                 .value_name("TXT")
                 .help("the song file to play")
                 .required(true),
         )
+        .arg(
+            Arg::with_name("gap-units")
+                .long("gap-units")
+                .value_name("UNITS")
+                .help("unit the GAP of the song file is stored in")
+                .possible_values(&["ms", "beats"])
+                .default_value("ms"),
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .help("flip the screen horizontally for mirror or teleprompter setups"),
+        )
+        .arg(
+            Arg::with_name("golden-style")
+                .long("golden-style")
+                .value_name("STYLE")
+                .help("how golden notes are emphasized besides their color")
+                .possible_values(&["color", "star", "blink", "glyph"])
+                .default_value("color"),
+        )
+        .arg(
+            Arg::with_name("pitch-algo")
+                .long("pitch-algo")
+                .value_name("ALGO")
+                .help("pitch detection, auto only does the full detection for unclear frames")
+                .possible_values(&["full", "auto"])
+                .default_value("full"),
+        )
+        .arg(
+            Arg::with_name("log-events")
+                .long("log-events")
+                .value_name("FILE")
+                .help("write the detected note of every frame to a file"),
+        )
+        .arg(
+            Arg::with_name("ghost")
+                .long("ghost")
+                .value_name("FILE")
+                .help("show the notes of an earlier run logged with --log-events"),
+        )
+        .arg(
+            Arg::with_name("note-report")
+                .long("note-report")
+                .help("print expected and detected pitch of every note when the song ends"),
+        )
+        .arg(
+            Arg::with_name("print-summary")
+                .long("print-summary")
+                .help("print score, grade and accuracy of every line when the song ends"),
+        )
         .get_matches();
 
-    println!("Ultrastar CLI player {} by @man0lis", VERSION);
+    // stdout only carries the reports, everything else goes to stderr or the tty
+    eprintln!("Ultrastar CLI player {} by @man0lis", VERSION);
 
     // get path from command line arguments, unwrap should not fail because argument is required
     let song_filepath = Path::new(matches.value_of("songfile").unwrap());
@@ -93,14 +151,37 @@ fn run() -> Result<()> {
         ultrastar_txt::parse_txt_song(song_filepath).chain_err(|| "could not parse song file")?;
     let header = txt_song.header;
     let lines = txt_song.lines;
+    let mut scorer = score::Scorer::new(&lines);
 
-    // prepare song
-    let bpms = header.bpm / 60.0 / 1000.0;
-    let gap = header.gap.unwrap_or(0.0);
+    // the ghost is read before the event log is created,
+    // so both can be the same file to race the last run and keep the new one
+    let ghost = match matches.value_of("ghost") {
+        Some(path) => Some(events::Ghost::load(Path::new(path))?),
+        None => None,
+    };
+    let mut event_log = match matches.value_of("log-events") {
+        Some(path) => Some(events::EventLog::create(Path::new(path))?),
+        None => None,
+    };
 
-    let mut line_iter = lines.into_iter();
-    let mut current_line = line_iter.next();
-    let mut next_line = line_iter.next();
+    // prepare song
+    // unwrap should not fail because clap checks the possible values
+    let gap_units = timing::GapUnits::from_arg(matches.value_of("gap-units").unwrap()).unwrap();
+    let raw_gap = header.gap.unwrap_or(0.0);
+    if timing::is_suspicious_gap(raw_gap, gap_units) {
+        eprintln!(
+            "warning: GAP of {} ms is suspiciously small, \
+             if it is given in beats ({:.0} ms) use --gap-units beats",
+            raw_gap,
+            timing::beats_to_ms(raw_gap, header.bpm)
+        );
+    }
+    let gap = timing::gap_to_ms(raw_gap, header.bpm, gap_units);
+    let mirror = matches.is_present("mirror");
+    // unwrap should not fail because clap checks the possible values
+    let golden_style =
+        draw::GoldenStyle::from_arg(matches.value_of("golden-style").unwrap()).unwrap();
+    let pitch_algo = pitch::Algorithm::from_arg(matches.value_of("pitch-algo").unwrap()).unwrap();
 
     // construct path and uri to audio file
     let audio_path = header.audio_path;
@@ -138,9 +219,10 @@ fn run() -> Result<()> {
             let max_volume = pitch::get_max_amplitude(buffer_f32.as_ref());
             let mut dominant_note = detected_note_capture.lock().unwrap();
             *dominant_note = if max_volume > 0.1 {
-                Some(pitch::get_dominant_note(
+                Some(pitch::get_note(
                     buffer_f32.as_ref(),
                     SAMPLE_RATE as f64,
+                    pitch_algo,
                 ))
             } else {
                 None
@@ -160,14 +242,12 @@ fn run() -> Result<()> {
         .set_property("uri", &uri)
         .chain_err(|| "can't set uri property on playbin")?;
 
-    println!("Playing {} by {}...\n", header.title, header.artist);
+    eprintln!("Playing {} by {}...\n", header.title, header.artist);
 
     // Start playing
     let ret = playbin.set_state(gst::State::Playing);
     assert_ne!(ret, gst::StateChangeReturn::Failure);
 
-    // connect to the bus
-    let bus = playbin.get_bus().unwrap();
     let mut custom_data = CustomData {
         playbin: playbin,
         playing: false,
@@ -178,20 +258,94 @@ fn run() -> Result<()> {
     thread::spawn(capture_thread);
 
     // get access to terminal
-    //let stdin = stdin();
-    //let mut stdout = stdout();
-    let mut stdout = AlternateScreen::from(stdout());
+    // raw mode so quitting with q or ctrl-c ends the song instead of killing the player
+    // drawn on the tty so stdout can be redirected, keys are read from the tty as well
+    let tty = termion::get_tty().chain_err(|| "could not open terminal")?;
+    let tty = draw::RawTty::new(tty)?;
+    let mut screen = AlternateScreen::from(tty);
 
     // clear screen
-    write!(stdout, "{}", termion::clear::All).chain_err(|| "could not write to stdout")?;
+    write!(screen, "{}", termion::clear::All).chain_err(|| "could not write to terminal")?;
+
+    let options = PlayOptions {
+        gap: gap,
+        bpm: header.bpm,
+        mirror: mirror,
+        golden_style: golden_style,
+        ghost: ghost,
+    };
+    // errors are kept until the pipeline is shut down and the summary is printed
+    let result = play(
+        &mut custom_data,
+        &mut screen,
+        lines,
+        &detected_note,
+        &options,
+        &mut scorer,
+        &mut event_log,
+    );
+
+    // Shutdown pipeline
+    let ret = custom_data.playbin.set_state(gst::State::Null);
+    assert_ne!(ret, gst::StateChangeReturn::Failure);
+
+    let log_finished = match event_log {
+        Some(event_log) => event_log.finish(),
+        None => Ok(()),
+    };
+
+    // leave alternate screen so the output stays visible
+    drop(screen);
+    eprintln!("");
+
+    if matches.is_present("note-report") {
+        print!("{}", score::note_report(&scorer));
+    }
+    if matches.is_present("print-summary") {
+        print!("{}", score::summary(&scorer));
+    }
+    result?;
+    log_finished
+}
+
+/// settings that stay the same while the song is playing
+struct PlayOptions {
+    gap: f32,
+    bpm: f32,
+    mirror: bool,
+    golden_style: draw::GoldenStyle,
+    ghost: Option<events::Ghost>,
+}
+
+/// main loop, draws the lines until the song ends or the player quits
+fn play(
+    custom_data: &mut CustomData,
+    screen: &mut AlternateScreen<draw::RawTty>,
+    lines: Vec<ultrastar_txt::Line>,
+    detected_note: &Mutex<Option<LetterOctave>>,
+    options: &PlayOptions,
+    scorer: &mut score::Scorer,
+    event_log: &mut Option<events::EventLog>,
+) -> Result<()> {
+    let mut line_iter = lines.into_iter();
+    let mut current_line = line_iter.next();
+    let mut next_line = line_iter.next();
+
+    // connect to the bus
+    let bus = custom_data.playbin.get_bus().unwrap();
+    let mut stdin = termion::async_stdin().bytes();
 
-    // begin main loop
     while !custom_data.terminate {
+        match stdin.next() {
+            Some(Ok(b'q')) | Some(Ok(3)) => custom_data.terminate = true,
+            _ => (),
+        }
+
         let msg = bus.timed_pop(10 * gst::MSECOND);
 
         match msg {
             Some(msg) => {
-                handle_message(&mut custom_data, &msg);
+                handle_message(custom_data, &msg);
             }
             None => {
                 if custom_data.playing {
@@ -211,26 +365,39 @@ fn run() -> Result<()> {
                     }
                     // get note from capture thread
                     let dominant_note = detected_note.lock().unwrap().clone();
+                    let position_ms = position.mseconds().unwrap_or(0);
+                    if let Some(ref mut event_log) = *event_log {
+                        event_log.log(position_ms, dominant_note)?;
+                    }
+                    let ghost_note =
+                        options.ghost.as_ref().and_then(|ghost| ghost.note_at(position_ms));
                     // calculate current beat
-                    let position_ms = position.mseconds().unwrap_or(0) as f32;
-                    // don't know why I need the 4.0 but its in the
-                    // original game and its not working without it
-                    let beat = (position_ms - gap) * (bpms * 4.0);
+                    let beat = timing::beat_at(position_ms, options.gap, options.bpm);
+                    scorer.record(beat, dominant_note);
 
                     let next_line_start = if next_line.is_some() {
                         next_line.clone().unwrap().start
                     } else {
                         // last line reached, make next if always fail
-                        beat as i32 + 100
+                        (beat as i32).saturating_add(100)
                     };
+                    let (term_width, _term_height) = draw::terminal_size(screen)?;
                     if beat > next_line_start as f32 {
                         // reprint current line to avoid stale highlights
                         if let &Some(ref line) = &current_line {
                             write!(
-                                stdout,
+                                screen,
                                 "{}",
-                                draw::generate_screen(line, beat + 100.0, dominant_note)?
-                            ).chain_err(|| "could not write to stdout")?;
+                                draw::generate_screen(
+                                    line,
+                                    beat + 100.0,
+                                    term_width,
+                                    dominant_note,
+                                    ghost_note,
+                                    options.mirror,
+                                    options.golden_style,
+                                )?
+                            ).chain_err(|| "could not write to terminal")?;
                         }
 
                         if next_line.is_some() {
@@ -238,29 +405,30 @@ fn run() -> Result<()> {
                         };
                         next_line = line_iter.next();
                         // clear screen
-                        write!(stdout, "{}", termion::clear::All)
-                            .chain_err(|| "could not write to stdout")?;
+                        write!(screen, "{}", termion::clear::All)
+                            .chain_err(|| "could not write to terminal")?;
                     }
 
                     // print current lyric line
                     if let &Some(ref line) = &current_line {
                         write!(
-                            stdout,
+                            screen,
                             "{}",
-                            draw::generate_screen(line, beat, dominant_note)?
-                        ).chain_err(|| "could not write to stdout")?;
+                            draw::generate_screen(
+                                line,
+                                beat,
+                                term_width,
+                                dominant_note,
+                                ghost_note,
+                                options.mirror,
+                                options.golden_style,
+                            )?
+                        ).chain_err(|| "could not write to terminal")?;
                     }
                 }
             }
         }
     }
-    // end main loop
-
-    // Shutdown pipeline
-    let ret = custom_data.playbin.set_state(gst::State::Null);
-    assert_ne!(ret, gst::StateChangeReturn::Failure);
-
-    println!("");
     Ok(())
 }
 
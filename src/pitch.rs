@@ -1,5 +1,34 @@
 use pitch_calc::*;
 
+// range of notes that can be detected
+const FIRST_TONE: LetterOctave = LetterOctave(Letter::C, 2);
+const LAST_TONE: LetterOctave = LetterOctave(Letter::A, 5);
+
+/// zero crossing estimates below this confidence fall back to the full detection
+const MIN_ZERO_CROSSING_CONFIDENCE: f64 = 0.9;
+/// sub-harmonics of the zero crossing estimate that are weighted on the fast path,
+/// a loud overtone makes the crossings regular at a multiple of the sung frequency
+const SUB_HARMONICS: u32 = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// autocorrelation for every semitone
+    Full,
+    /// autocorrelation only for the sub-harmonics of the zero crossings,
+    /// for every semitone if they are irregular
+    Auto,
+}
+
+impl Algorithm {
+    pub fn from_arg(arg: &str) -> Option<Algorithm> {
+        match arg {
+            "full" => Some(Algorithm::Full),
+            "auto" => Some(Algorithm::Auto),
+            _ => None,
+        }
+    }
+}
+
 fn do_autocorrelation_with_freq(samples: &[f32], sample_rate: f64, freq: f64) -> f64 {
     let samples_per_period = (sample_rate / freq).round() as usize;
     let correlating_sample_iter = samples.iter().skip(samples_per_period);
@@ -8,30 +37,30 @@ fn do_autocorrelation_with_freq(samples: &[f32], sample_rate: f64, freq: f64) ->
     1.0 - accum_dist as f64 / samples.len() as f64
 }
 
-fn get_note_wieghts(samples: &[f32], sample_rate: f64) -> Vec<(LetterOctave, f64)> {
-    let first_tone = LetterOctave(Letter::C, 2);
-    let last_tone = LetterOctave(Letter::A, 5);
+fn get_note_wieght(samples: &[f32], sample_rate: f64, step: i32) -> (LetterOctave, f64) {
+    let step_float = step as f32;
+    (
+        Step(step_float).to_letter_octave(),
+        do_autocorrelation_with_freq(samples, sample_rate, Step(step_float).to_hz().hz() as f64),
+    )
+}
 
-    let first_semitone = first_tone.to_step().step() as i32;
-    let last_semitone = last_tone.to_step().step() as i32;
+fn get_note_wieghts(samples: &[f32], sample_rate: f64) -> Vec<(LetterOctave, f64)> {
+    let first_semitone = FIRST_TONE.to_step().step() as i32;
+    let last_semitone = LAST_TONE.to_step().step() as i32;
 
     (first_semitone..last_semitone)
-        .map(|step| {
-            let step_float = step as f32;
-            (
-                Step(step_float).to_letter_octave(),
-                do_autocorrelation_with_freq(
-                    samples,
-                    sample_rate,
-                    Step(step_float).to_hz().hz() as f64,
-                ),
-            )
-        })
+        .map(|step| get_note_wieght(samples, sample_rate, step))
         .collect::<Vec<_>>()
 }
 
 pub fn get_dominant_note(samples: &[f32], sample_rate: f64) -> LetterOctave {
-    get_note_wieghts(samples, sample_rate)
+    get_strongest_note(&get_note_wieghts(samples, sample_rate))
+}
+
+/// note with the highest weight, the lowest note wins a tie
+fn get_strongest_note(weights: &[(LetterOctave, f64)]) -> LetterOctave {
+    weights
         .iter()
         .fold(
             (LetterOctave(Letter::C, 2), -1.0),
@@ -44,6 +73,149 @@ pub fn get_dominant_note(samples: &[f32], sample_rate: f64) -> LetterOctave {
         .0
 }
 
+/// estimates the frequency from the distance between rising zero crossings,
+/// the confidence is high when the distances are regular like for a clean tone
+fn get_zero_crossing_freq(samples: &[f32], sample_rate: f64) -> Option<(f64, f64)> {
+    // interpolated positions where the signal rises through zero
+    let crossings: Vec<f64> = samples
+        .windows(2)
+        .enumerate()
+        .filter(|&(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+        .map(|(index, pair)| index as f64 + (-pair[0] / (pair[1] - pair[0])) as f64)
+        .collect();
+    if crossings.len() < 3 {
+        return None;
+    }
+
+    let periods: Vec<f64> = crossings.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let mean = periods.iter().sum::<f64>() / periods.len() as f64;
+    let variance =
+        periods.iter().map(|period| (period - mean).powi(2)).sum::<f64>() / periods.len() as f64;
+    let confidence = 1.0 - (variance.sqrt() / mean).min(1.0);
+
+    Some((sample_rate / mean, confidence))
+}
+
+/// weighs only the sub-harmonics of the zero crossing estimate like the full detection does,
+/// so overtones and octaves are resolved the same way,
+/// None if the full detection is needed
+fn get_fast_note(samples: &[f32], sample_rate: f64) -> Option<LetterOctave> {
+    let freq = match get_zero_crossing_freq(samples, sample_rate) {
+        Some((freq, confidence)) if confidence >= MIN_ZERO_CROSSING_CONFIDENCE => freq,
+        _ => return None,
+    };
+
+    let first_semitone = FIRST_TONE.to_step().step() as i32;
+    let last_semitone = LAST_TONE.to_step().step() as i32;
+    // the neighbours as well because the semitone periods are rounded to whole samples
+    let mut steps: Vec<i32> = (1..SUB_HARMONICS + 1)
+        .map(|divisor| Hz((freq / divisor as f64) as f32).step().round() as i32)
+        .flat_map(|step| vec![step - 1, step, step + 1])
+        .filter(|&step| step >= first_semitone && step < last_semitone)
+        .collect();
+    if steps.is_empty() {
+        return None;
+    }
+    // ascending like the full detection, so ties are broken the same way
+    steps.sort();
+    steps.dedup();
+
+    let weights: Vec<_> = steps
+        .into_iter()
+        .map(|step| get_note_wieght(samples, sample_rate, step))
+        .collect();
+    Some(get_strongest_note(&weights))
+}
+
+pub fn get_note(samples: &[f32], sample_rate: f64, algorithm: Algorithm) -> LetterOctave {
+    match algorithm {
+        Algorithm::Full => get_dominant_note(samples, sample_rate),
+        Algorithm::Auto => get_fast_note(samples, sample_rate)
+            .unwrap_or_else(|| get_dominant_note(samples, sample_rate)),
+    }
+}
+
 pub fn get_max_amplitude(samples: &[f32]) -> f32 {
     samples.iter().map(|x| x.abs()).fold(0.0, f32::max)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: f64 = 44_100.0;
+    const FRAMES: usize = 2048;
+
+    fn sine(freq: f32) -> Vec<f32> {
+        (0..FRAMES)
+            .map(|i| (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    /// deterministic noise in -1..1 from a linear congruential generator
+    fn noise() -> Vec<f32> {
+        let mut state: u32 = 12345;
+        (0..FRAMES)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as f32 / 32_768.0 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean_tone_takes_fast_path() {
+        let samples = sine(440.0);
+        let (freq, confidence) = get_zero_crossing_freq(&samples, SAMPLE_RATE).unwrap();
+        assert!(confidence >= MIN_ZERO_CROSSING_CONFIDENCE);
+        assert!((freq - 440.0).abs() < 1.0);
+
+        // the full detection settles on the lowest octave whose period fits,
+        // the fast path has to show the same octave
+        let full = get_dominant_note(&samples, SAMPLE_RATE);
+        assert_eq!(get_fast_note(&samples, SAMPLE_RATE), Some(full));
+        assert_eq!(get_note(&samples, SAMPLE_RATE, Algorithm::Auto), full);
+    }
+
+    #[test]
+    fn loud_overtone_is_not_taken_for_the_note() {
+        // A3 whose third harmonic is louder than the fundamental like in a sung vowel
+        let samples: Vec<f32> = sine(220.0)
+            .iter()
+            .zip(sine(440.0).iter())
+            .zip(sine(660.0).iter())
+            .map(|((first, second), third)| 0.3 * first + 0.2 * second + third)
+            .collect();
+        // the zero crossings alone follow the overtone (E) with high confidence
+        let (freq, confidence) = get_zero_crossing_freq(&samples, SAMPLE_RATE).unwrap();
+        assert!(confidence >= MIN_ZERO_CROSSING_CONFIDENCE);
+        assert_eq!(Hz(freq as f32).letter(), Letter::E);
+
+        let full = get_dominant_note(&samples, SAMPLE_RATE);
+        assert_eq!(full.letter(), Letter::A);
+        assert_eq!(get_fast_note(&samples, SAMPLE_RATE), Some(full));
+        assert_eq!(get_note(&samples, SAMPLE_RATE, Algorithm::Auto), full);
+    }
+
+    #[test]
+    fn noisy_buffers_fall_back() {
+        let noise = noise();
+        let noisy_tone: Vec<f32> = sine(440.0)
+            .iter()
+            .zip(noise.iter())
+            .map(|(tone, noise)| tone + noise)
+            .collect();
+
+        for samples in &[noise, noisy_tone] {
+            if let Some((_, confidence)) = get_zero_crossing_freq(samples, SAMPLE_RATE) {
+                assert!(confidence < MIN_ZERO_CROSSING_CONFIDENCE);
+            }
+            assert_eq!(get_fast_note(samples, SAMPLE_RATE), None);
+            assert_eq!(
+                get_note(samples, SAMPLE_RATE, Algorithm::Auto),
+                get_dominant_note(samples, SAMPLE_RATE)
+            );
+        }
+    }
+}
@@ -0,0 +1,429 @@
+extern crate ultrastar_txt;
+
+use pitch_calc::*;
+
+/// detections while a single note of the song was due
+pub struct NoteResult {
+    pub line: usize,
+    pub start: i32,
+    pub duration: i32,
+    pub pitch: i32,
+    pub text: String,
+    pub golden: bool,
+    /// frames during the note, sung or not
+    pub frames: u32,
+    /// how often each pitch class was detected during the note
+    pub detected: [u32; 12],
+}
+
+impl NoteResult {
+    /// pitch class that was detected most often, None if nothing was sung
+    pub fn most_common(&self) -> Option<i32> {
+        let (class, count) = self.detected
+            .iter()
+            .enumerate()
+            .fold((0, 0), |(max_class, max_count), (class, &count)| {
+                if count > max_count {
+                    (class, count)
+                } else {
+                    (max_class, max_count)
+                }
+            });
+        if count > 0 {
+            Some(class as i32)
+        } else {
+            None
+        }
+    }
+
+    /// frames in which the expected pitch class was sung
+    pub fn hits(&self) -> u32 {
+        self.detected[pitch_class(self.pitch as f32) as usize]
+    }
+
+    /// semitones between the expected and the most common detected pitch class
+    pub fn offset(&self) -> Option<i32> {
+        self.most_common()
+            .map(|class| to_offset(class - pitch_class(self.pitch as f32)))
+    }
+}
+
+pub struct Scorer {
+    notes: Vec<NoteResult>,
+}
+
+impl Scorer {
+    pub fn new(lines: &[ultrastar_txt::Line]) -> Scorer {
+        let mut notes = Vec::new();
+        for (line_index, line) in lines.iter().enumerate() {
+            for note in line.notes.iter() {
+                // freestyle notes are not graded
+                let (start, duration, pitch, text, golden) = match note {
+                    &ultrastar_txt::Note::Regular {
+                        start,
+                        duration,
+                        pitch,
+                        ref text,
+                    } => (start, duration, pitch, text, false),
+                    &ultrastar_txt::Note::Golden {
+                        start,
+                        duration,
+                        pitch,
+                        ref text,
+                    } => (start, duration, pitch, text, true),
+                    _ => continue,
+                };
+                notes.push(NoteResult {
+                    line: line_index,
+                    start: start,
+                    duration: duration,
+                    pitch: pitch,
+                    text: text.clone(),
+                    golden: golden,
+                    frames: 0,
+                    detected: [0; 12],
+                });
+            }
+        }
+        Scorer { notes: notes }
+    }
+
+    /// counts the detected note towards the note that is due at the given beat
+    pub fn record(&mut self, beat: f32, detected: Option<LetterOctave>) {
+        let current = self.notes.iter_mut().find(|note| {
+            note.start as f32 <= beat && beat < note.start.saturating_add(note.duration) as f32
+        });
+        if let Some(note) = current {
+            note.frames = note.frames.saturating_add(1);
+            if let Some(detected) = detected {
+                let count = &mut note.detected[pitch_class(detected.step()) as usize];
+                *count = count.saturating_add(1);
+            }
+        }
+    }
+
+    /// score out of 10000 like in the original game, golden notes count double
+    pub fn score(&self) -> u32 {
+        let mut achieved = 0.0;
+        let mut possible = 0.0;
+        for note in self.notes.iter() {
+            let weight = note.duration.max(0) as f64 * if note.golden { 2.0 } else { 1.0 };
+            achieved += weight * accuracy(note.hits(), note.frames);
+            possible += weight;
+        }
+        if possible > 0.0 {
+            (achieved / possible * 10000.0).round() as u32
+        } else {
+            0
+        }
+    }
+
+    /// ratio of frames sung right for every line, None if the line was never reached
+    pub fn line_accuracies(&self) -> Vec<(String, Option<f64>)> {
+        let mut lines: Vec<(String, u32, u32)> = Vec::new();
+        for note in self.notes.iter() {
+            while lines.len() <= note.line {
+                lines.push((String::new(), 0, 0));
+            }
+            let line = &mut lines[note.line];
+            line.0.push_str(&note.text);
+            line.1 = line.1.saturating_add(note.hits());
+            line.2 = line.2.saturating_add(note.frames);
+        }
+        lines
+            .into_iter()
+            .map(|(text, hits, frames)| {
+                let accuracy = if frames > 0 {
+                    Some(accuracy(hits, frames))
+                } else {
+                    None
+                };
+                (text, accuracy)
+            })
+            .collect()
+    }
+
+    pub fn notes(&self) -> &[NoteResult] {
+        &self.notes
+    }
+}
+
+/// table of expected and detected pitch for every note, meant for checking charts
+pub fn note_report(scorer: &Scorer) -> String {
+    // offset shared by most notes, hints at a transposed chart
+    let mut offset_counts = [0u32; 12];
+    for offset in scorer.notes().iter().filter_map(|note| note.offset()) {
+        let count = &mut offset_counts[(offset + 5) as usize];
+        *count = count.saturating_add(1);
+    }
+    let common_offset = offset_counts
+        .iter()
+        .enumerate()
+        .filter(|&(_, &count)| count > 1)
+        .max_by_key(|&(_, &count)| count)
+        .map(|(index, _)| index as i32 - 5)
+        .filter(|&offset| offset != 0);
+
+    let mut report = format!(
+        "{:>6}  {:<16}{:<10}{:<10}{:>6}\n",
+        "beat", "text", "expected", "detected", "offset"
+    );
+    for note in scorer.notes() {
+        let detected = match note.most_common() {
+            Some(class) => format!("{:?}", Step(class as f32).letter()),
+            None => "-".to_string(),
+        };
+        let (offset, remark) = match note.offset() {
+            Some(0) => ("0".to_string(), ""),
+            Some(offset) if Some(offset) == common_offset => (offset.to_string(), "transposed?"),
+            Some(offset) => (offset.to_string(), "hard note?"),
+            None => ("-".to_string(), ""),
+        };
+        report.push_str(&format!(
+            "{:>6}  {:<16}{:<10}{:<10}{:>6}  {}\n",
+            note.start,
+            note.text.trim(),
+            format!("{:?}", Step(note.pitch as f32).letter()),
+            detected,
+            offset,
+            remark,
+        ));
+    }
+    if let Some(offset) = common_offset {
+        report.push_str(&format!(
+            "\nmost notes are off by {} semitones, the chart is likely transposed\n",
+            offset
+        ));
+    }
+    report
+}
+
+/// plain text summary without colors, meant for logging or piping
+pub fn summary(scorer: &Scorer) -> String {
+    let score = scorer.score();
+    let mut summary = format!("score: {}\ngrade: {}\n\n", score, grade(score));
+    for (text, accuracy) in scorer.line_accuracies() {
+        let accuracy = match accuracy {
+            Some(accuracy) => format!("{:.0}%", accuracy * 100.0),
+            None => "-".to_string(),
+        };
+        summary.push_str(&format!("{:>5}  {}\n", accuracy, text.trim()));
+    }
+    summary
+}
+
+/// ranks as named in the original game
+fn grade(score: u32) -> &'static str {
+    if score < 2000 {
+        "Tone Deaf"
+    } else if score < 4000 {
+        "Amateur"
+    } else if score < 5000 {
+        "Wannabe"
+    } else if score < 6000 {
+        "Hopeful"
+    } else if score < 7000 {
+        "Rising Star"
+    } else if score < 8000 {
+        "Lead Singer"
+    } else if score < 9000 {
+        "Superstar"
+    } else {
+        "Ultrastar"
+    }
+}
+
+fn accuracy(hits: u32, frames: u32) -> f64 {
+    if frames > 0 {
+        hits as f64 / frames as f64
+    } else {
+        0.0
+    }
+}
+
+fn pitch_class(step: f32) -> i32 {
+    (step.round() as i32).rem_euclid(12)
+}
+
+/// maps a difference of pitch classes to the closest interval in -5..6
+fn to_offset(diff: i32) -> i32 {
+    let diff = diff.rem_euclid(12);
+    if diff > 6 {
+        diff - 12
+    } else {
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timing;
+
+    // 480 bpm, so a beat lasts 31.25 ms
+    const BPM: f32 = 480.0;
+    const GAP_MS: u64 = 1000;
+    // every note is 64 beats (2 s) long and followed by a pause of 16 beats (500 ms)
+    const NOTE_MS: u64 = 2000;
+    const NOTE_STEP_MS: u64 = 2500;
+    // three hours of notes
+    const NOTES: usize = 4320;
+
+    /// very long medley with two notes per line
+    fn medley() -> Vec<ultrastar_txt::Line> {
+        (0..NOTES / 2)
+            .map(|line| {
+                let start = line as i32 * 160;
+                ultrastar_txt::Line {
+                    start: start,
+                    rel: None,
+                    notes: vec![
+                        ultrastar_txt::Note::Regular {
+                            start: start,
+                            duration: 64,
+                            pitch: 0,
+                            text: "la ".to_string(),
+                        },
+                        ultrastar_txt::Note::Regular {
+                            start: start + 80,
+                            duration: 64,
+                            pitch: 7,
+                            text: "lu ".to_string(),
+                        },
+                    ],
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn long_medley_keeps_frames_on_their_notes() {
+        let lines = medley();
+        let mut scorer = Scorer::new(&lines);
+        assert_eq!(scorer.notes().len(), NOTES);
+
+        for index in 0..NOTES {
+            let pitch = scorer.notes()[index].pitch;
+            // every fourth note is sung a semitone too high
+            let sung = if index % 4 == 3 { pitch + 1 } else { pitch };
+            let sung = Some(Step(sung as f32).to_letter_octave());
+
+            let start_ms = GAP_MS + index as u64 * NOTE_STEP_MS;
+            // right after the start, in the middle, right before the end and in the pause
+            for &offset in &[5, NOTE_MS / 2, NOTE_MS - 5, NOTE_MS + 250] {
+                let beat = timing::beat_at(start_ms + offset, GAP_MS as f32, BPM);
+                scorer.record(beat, sung);
+            }
+        }
+
+        for (index, note) in scorer.notes().iter().enumerate() {
+            // the pause frame must not count towards any note
+            assert_eq!(note.frames, 3, "note {}", index);
+            let expected_hits = if index % 4 == 3 { 0 } else { 3 };
+            assert_eq!(note.hits(), expected_hits, "note {}", index);
+        }
+        assert_eq!(scorer.score(), 7500);
+        assert!(summary(&scorer).starts_with("score: 7500\ngrade: Lead Singer\n"));
+    }
+
+    /// one line with a note every 4 beats, each 4 beats long
+    fn chart(pitches: &[i32]) -> Vec<ultrastar_txt::Line> {
+        let notes = pitches
+            .iter()
+            .enumerate()
+            .map(|(index, &pitch)| ultrastar_txt::Note::Regular {
+                start: index as i32 * 4,
+                duration: 4,
+                pitch: pitch,
+                text: format!("n{} ", index),
+            })
+            .collect();
+        vec![
+            ultrastar_txt::Line {
+                start: 0,
+                rel: None,
+                notes: notes,
+            },
+        ]
+    }
+
+    /// sings every note of the chart at the given pitches for all of its beats
+    fn sing(scorer: &mut Scorer, sung: &[i32]) {
+        for (index, &pitch) in sung.iter().enumerate() {
+            for beat in 0..4 {
+                let beat = (index * 4 + beat) as f32;
+                scorer.record(beat, Some(Step(pitch as f32).to_letter_octave()));
+            }
+        }
+    }
+
+    fn remarks(report: &str, remark: &str) -> Vec<String> {
+        report
+            .lines()
+            .filter(|row| row.ends_with(remark))
+            .map(|row| row.split_whitespace().nth(1).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn consistent_offset_is_reported_as_transposed() {
+        let lines = chart(&[0, 4, 7, 12]);
+        let mut scorer = Scorer::new(&lines);
+        // everything two semitones too high, the octave does not matter
+        sing(&mut scorer, &[2, 6, 9, 2]);
+
+        let report = note_report(&scorer);
+        assert_eq!(remarks(&report, "transposed?"), vec!["n0", "n1", "n2", "n3"]);
+        assert!(remarks(&report, "hard note?").is_empty());
+        assert!(report.contains("most notes are off by 2 semitones"));
+    }
+
+    #[test]
+    fn single_outlier_is_reported_as_hard_note() {
+        let lines = chart(&[0, 4, 7, 12]);
+        let mut scorer = Scorer::new(&lines);
+        sing(&mut scorer, &[0, 4, 10, 12]);
+
+        let report = note_report(&scorer);
+        assert_eq!(remarks(&report, "hard note?"), vec!["n2"]);
+        assert!(remarks(&report, "transposed?").is_empty());
+        assert!(!report.contains("most notes are off"));
+    }
+
+    #[test]
+    fn grades_follow_the_original_ranks() {
+        assert_eq!(grade(0), "Tone Deaf");
+        assert_eq!(grade(1999), "Tone Deaf");
+        assert_eq!(grade(2000), "Amateur");
+        assert_eq!(grade(5000), "Hopeful");
+        assert_eq!(grade(8999), "Superstar");
+        assert_eq!(grade(9000), "Ultrastar");
+        assert_eq!(grade(10000), "Ultrastar");
+    }
+
+    #[test]
+    fn summary_lists_every_line() {
+        let mut lines = chart(&[0, 4]);
+        lines.push(ultrastar_txt::Line {
+            start: 8,
+            rel: None,
+            notes: vec![
+                ultrastar_txt::Note::Golden {
+                    start: 8,
+                    duration: 4,
+                    pitch: 7,
+                    text: "n2 ".to_string(),
+                },
+            ],
+        });
+        let mut scorer = Scorer::new(&lines);
+        // the second note is missed and the song is quit before the second line
+        sing(&mut scorer, &[0, 5]);
+
+        // the golden note counts double, so 4 of 16 weighted beats are right
+        assert_eq!(
+            summary(&scorer),
+            "score: 2500\ngrade: Amateur\n\n  50%  n0 n1\n    -  n2\n"
+        );
+    }
+}
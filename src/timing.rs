@@ -0,0 +1,74 @@
+/// gaps below this are more likely beats than milliseconds
+const SUSPICIOUS_GAP_MS: f32 = 500.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GapUnits {
+    Milliseconds,
+    Beats,
+}
+
+impl GapUnits {
+    pub fn from_arg(arg: &str) -> Option<GapUnits> {
+        match arg {
+            "ms" => Some(GapUnits::Milliseconds),
+            "beats" => Some(GapUnits::Beats),
+            _ => None,
+        }
+    }
+}
+
+/// beat of the song at the given position, bpm as given in the song header
+pub fn beat_at(position_ms: u64, gap_ms: f32, bpm: f32) -> f32 {
+    // calculated in f64, f32 milliseconds get imprecise after a few hours
+    // don't know why I need the 4.0 but its in the
+    // original game and its not working without it
+    ((position_ms as f64 - gap_ms as f64) * (bpm as f64 / 60.0 / 1000.0 * 4.0)) as f32
+}
+
+/// converts beats to milliseconds, bpm as given in the song header
+pub fn beats_to_ms(beats: f32, bpm: f32) -> f32 {
+    // same factor 4.0 as in beat_at
+    beats / (bpm / 60.0 / 1000.0 * 4.0)
+}
+
+/// returns the gap in milliseconds regardless of the unit it was stored in
+pub fn gap_to_ms(gap: f32, bpm: f32, units: GapUnits) -> f32 {
+    match units {
+        GapUnits::Milliseconds => gap,
+        GapUnits::Beats => beats_to_ms(gap, bpm),
+    }
+}
+
+pub fn is_suspicious_gap(gap: f32, units: GapUnits) -> bool {
+    units == GapUnits::Milliseconds && gap > 0.0 && gap < SUSPICIOUS_GAP_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 300 bpm, so a beat lasts 50 ms and the GAP of 2000 ms is 40 beats
+    const BPM: f32 = 300.0;
+
+    #[test]
+    fn beats_and_milliseconds_align() {
+        let from_ms = gap_to_ms(2000.0, BPM, GapUnits::Milliseconds);
+        let from_beats = gap_to_ms(40.0, BPM, GapUnits::Beats);
+        assert_eq!(from_ms, 2000.0);
+        assert_eq!(from_beats, 2000.0);
+
+        for &gap in &[from_ms, from_beats] {
+            assert_eq!(beat_at(0, gap, BPM), -40.0);
+            assert_eq!(beat_at(2000, gap, BPM), 0.0);
+            assert_eq!(beat_at(3000, gap, BPM), 20.0);
+        }
+    }
+
+    #[test]
+    fn only_small_millisecond_gaps_are_suspicious() {
+        assert!(is_suspicious_gap(58.0, GapUnits::Milliseconds));
+        assert!(!is_suspicious_gap(2720.0, GapUnits::Milliseconds));
+        assert!(!is_suspicious_gap(0.0, GapUnits::Milliseconds));
+        assert!(!is_suspicious_gap(58.0, GapUnits::Beats));
+    }
+}